@@ -5,6 +5,7 @@
 //!   1. How to enable metering in a module
 //!   2. How to meter a specific function call
 //!   3. How to make execution fails if cost exceeds a given limit
+//!   4. How a host (imported) function can charge metering points itself
 //!
 //! You can run the example directly by executing in Wasmer root:
 //!
@@ -18,10 +19,34 @@ use anyhow::bail;
 use std::sync::Arc;
 use wasmer::wasmparser::Operator;
 use wasmer::CompilerConfig;
-use wasmer::{imports, wat2wasm, Instance, Module, Store};
+use wasmer::{
+    imports, wat2wasm, Function, HostEnvInitError, Instance, LazyInit, Module, Store, WasmerEnv,
+};
 use wasmer_compiler_cranelift::Cranelift;
 use wasmer_engine_jit::JIT;
-use wasmer_middlewares::metering::{get_remaining_points, set_remaining_points, Metering};
+use wasmer_middlewares::metering::{
+    get_operator_histogram, get_points_consumed, get_remaining_points, metering_points,
+    set_remaining_points, try_charge, DynamicCostOperator, Metering, MeteringConsumptionMode,
+    MeteringPoints, OperatorClass,
+};
+
+/// Environment for the `charge_points` host function below, giving it a
+/// handle to the `Instance` it was called from. `LazyInit` is filled in via
+/// `init_with_instance`, which Wasmer calls right after instantiation and,
+/// critically, before any Wasm `(start)` function runs — unlike a
+/// manually-populated cell assigned after `Instance::new` returns, this
+/// can't be observed empty by a host function the module calls eagerly.
+#[derive(Clone, Default)]
+struct MeteringEnv {
+    instance: LazyInit<Instance>,
+}
+
+impl WasmerEnv for MeteringEnv {
+    fn init_with_instance(&mut self, instance: &Instance) -> Result<(), HostEnvInitError> {
+        self.instance.initialize(instance.clone());
+        Ok(())
+    }
+}
 
 fn main() -> anyhow::Result<()> {
     // Let's declare the Wasm module.
@@ -156,6 +181,191 @@ fn main() -> anyhow::Result<()> {
 
     println!("Remaining points: {:?}", remaining_points);
 
+    // Host functions (imports) are invisible to the Wasm operator cost
+    // function above: they run "for free" unless they charge for their own
+    // work explicitly. Let's build a tiny module that imports a host
+    // function and have that host function charge points proportional to
+    // the work it's asked to do.
+    println!("Charging metering points from a host function...");
+
+    let host_wasm_bytes = wat2wasm(
+        br#"
+(module
+  (import "host" "charge_points" (func $charge_points (param i32)))
+  (func $do_work_f (export "do_work") (param $amount i32)
+    local.get $amount
+    call $charge_points))
+"#,
+    )?;
+
+    let metering = Arc::new(Metering::new(10, cost_function));
+    let mut compiler_config = Cranelift::default();
+    compiler_config.push_middleware(metering);
+    let store = Store::new(&JIT::new(compiler_config).engine());
+    let module = Module::new(&store, host_wasm_bytes)?;
+
+    fn charge_points(env: &MeteringEnv, amount: i32) -> Result<(), wasmer::RuntimeError> {
+        let instance = env
+            .instance
+            .get_ref()
+            .expect("instance not yet initialized");
+        try_charge(instance, amount as u64)
+            .map_err(|error| wasmer::RuntimeError::new(error.to_string()))
+    }
+    let import_object = imports! {
+        "host" => {
+            "charge_points" => Function::new_native_with_env(&store, MeteringEnv::default(), charge_points),
+        },
+    };
+    let instance = Instance::new(&module, &import_object)?;
+
+    let do_work = instance
+        .exports
+        .get_function("do_work")?
+        .native::<i32, ()>()?;
+
+    // We have 10 points; `local.get $amount` costs 1 (flushed right before
+    // the `call`), then asking the host function to charge 4 more takes us
+    // to 5 remaining.
+    do_work.call(4)?;
+    assert_eq!(metering_points(&instance), MeteringPoints::Remaining(5));
+
+    // Asking it to charge more than remains traps, via the `RuntimeError`
+    // we turned the `MeteringError` into above.
+    match do_work.call(100) {
+        Ok(()) => bail!("expected the host function to run out of points"),
+        Err(_) => println!("Calling `do_work` failed: not enough gas points remaining."),
+    }
+    assert_eq!(metering_points(&instance), MeteringPoints::Exhausted);
+
+    // `MeteringConsumptionMode::Lazy` skips the exhaustion check for blocks
+    // that don't reach an observable checkpoint, but it must still flush
+    // any cost accumulated earlier in the block before checking at one.
+    // Here `local.get` alone already costs more than the budget; the check
+    // at the `memory.grow` checkpoint right after it must see that cost,
+    // not a stale, unflushed remaining-points value.
+    println!("Verifying Lazy mode flushes pending cost before a memory.grow checkpoint...");
+
+    let lazy_cost_function = |operator: &Operator| -> u64 {
+        match operator {
+            Operator::LocalGet { .. } => 40,
+            _ => 0,
+        }
+    };
+    let lazy_wasm_bytes = wat2wasm(
+        br#"
+(module
+  (memory 1 10)
+  (func $grow_f (export "grow") (param $pages i32) (result i32)
+    local.get $pages
+    memory.grow))
+"#,
+    )?;
+    let metering = Arc::new(Metering::new_with_mode(
+        30,
+        lazy_cost_function,
+        MeteringConsumptionMode::Lazy,
+    ));
+    let mut compiler_config = Cranelift::default();
+    compiler_config.push_middleware(metering);
+    let store = Store::new(&JIT::new(compiler_config).engine());
+    let module = Module::new(&store, lazy_wasm_bytes)?;
+    let import_object = imports! {};
+    let instance = Instance::new(&module, &import_object)?;
+    let grow = instance
+        .exports
+        .get_function("grow")?
+        .native::<i32, i32>()?;
+
+    match grow.call(1) {
+        Ok(result) => bail!("expected `grow` to trap on exhaustion, found: {}", result),
+        Err(_) => println!("Calling `grow` failed: not enough gas points remaining."),
+    }
+    assert_eq!(metering_points(&instance), MeteringPoints::Exhausted);
+
+    // `Metering::calibrating` never traps: it instruments the same blocks,
+    // but accumulates the points that would have been consumed and an
+    // execution histogram instead, so a user can tune a `cost_function`
+    // from real operator frequencies rather than a guess.
+    println!("Calibrating a cost function by profiling operator frequencies...");
+
+    let calibration_wasm_bytes = wat2wasm(
+        br#"
+(module
+  (type $add_t (func (param i32) (result i32)))
+  (func $add_one_f (type $add_t) (param $value i32) (result i32)
+    local.get $value
+    i32.const 1
+    i32.add)
+  (export "add_one" (func $add_one_f)))
+"#,
+    )?;
+    let metering = Arc::new(Metering::calibrating(cost_function));
+    let mut compiler_config = Cranelift::default();
+    compiler_config.push_middleware(metering);
+    let store = Store::new(&JIT::new(compiler_config).engine());
+    let module = Module::new(&store, calibration_wasm_bytes)?;
+    let import_object = imports! {};
+    let instance = Instance::new(&module, &import_object)?;
+    let add_one = instance
+        .exports
+        .get_function("add_one")?
+        .native::<i32, i32>()?;
+
+    // Calling it three times never traps, unlike the enforcing `Metering`
+    // above with the same cost function and a limit of 10.
+    add_one.call(1)?;
+    add_one.call(1)?;
+    add_one.call(1)?;
+
+    // Each call costs 4 points (`local.get` + `i32.const` + `i32.add`), so
+    // three calls accumulate 12, and each operator executed three times.
+    assert_eq!(get_points_consumed(&instance), 12);
+    let histogram = get_operator_histogram(&instance);
+    assert_eq!(histogram[&OperatorClass::LocalsAndGlobals], 3);
+    assert_eq!(histogram[&OperatorClass::Numeric], 6);
+
+    // A flat `cost_function` can't price `memory.grow` fairly: the number
+    // of pages requested is only known at runtime. `with_dynamic_cost` lets
+    // us charge per page instead, computed and checked right before the
+    // instruction executes.
+    println!("Charging memory.grow proportionally to the number of pages requested...");
+
+    let dynamic_wasm_bytes = wat2wasm(
+        br#"
+(module
+  (memory 1 10)
+  (func $grow_f (export "grow") (param $pages i32) (result i32)
+    local.get $pages
+    memory.grow))
+"#,
+    )?;
+    let metering = Arc::new(
+        Metering::new(100, |_: &Operator| 0).with_dynamic_cost(DynamicCostOperator::MemoryGrow, 10),
+    );
+    let mut compiler_config = Cranelift::default();
+    compiler_config.push_middleware(metering);
+    let store = Store::new(&JIT::new(compiler_config).engine());
+    let module = Module::new(&store, dynamic_wasm_bytes)?;
+    let import_object = imports! {};
+    let instance = Instance::new(&module, &import_object)?;
+    let grow = instance
+        .exports
+        .get_function("grow")?
+        .native::<i32, i32>()?;
+
+    // Growing by 3 pages at 10 points/page costs 30, leaving 70.
+    grow.call(3)?;
+    assert_eq!(get_remaining_points(&instance), 70);
+
+    // Growing by 10 more pages would cost 100, more than the 70 remaining;
+    // the runtime charge must trap instead of under-charging a flat rate.
+    match grow.call(10) {
+        Ok(result) => bail!("expected `grow` to trap on exhaustion, found: {}", result),
+        Err(_) => println!("Calling `grow` failed: not enough gas points remaining."),
+    }
+    assert_eq!(metering_points(&instance), MeteringPoints::Exhausted);
+
     Ok(())
 }
 
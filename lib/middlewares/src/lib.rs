@@ -0,0 +1,13 @@
+//! This crate contains ready-to-use middlewares for Wasmer runtime.
+//!
+//! Middlewares are used to allow changing the behavior of WebAssembly
+//! programs that run within Wasmer. Each middleware hooks into the
+//! compilation pipeline and rewrites the input Wasm module before it is
+//! passed on to a compiler backend.
+//!
+//! Currently supported middlewares:
+//!   - `metering`: gas-style metering of Wasm execution.
+
+pub mod metering;
+
+pub use self::metering::Metering;
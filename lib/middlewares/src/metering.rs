@@ -0,0 +1,905 @@
+//! A middleware that facilitates metering of Wasm execution.
+//!
+//! The `Metering` middleware tracks how many "points" a Wasm execution has
+//! consumed so far, and traps once the configured limit has been
+//! exceeded. The cost of each operator is given by a user-supplied
+//! `cost_function`, which lets embedders weight instructions however they
+//! see fit (for example, pricing a Wasm instruction set in "gas").
+//!
+//! Internally, the middleware groups operators into basic blocks and
+//! injects, at the boundary of each block, code that updates a pair of
+//! globals holding the remaining points and whether they have been
+//! exhausted. See [`MeteringConsumptionMode`] for the two supported
+//! strategies for when the exhaustion check is actually emitted.
+//!
+//! A flat `cost_function` assigns every occurrence of an opcode the same
+//! price, which under-charges instructions whose actual cost scales with a
+//! runtime argument, like `memory.grow` or the bulk `memory.copy`/
+//! `memory.fill` operators. [`Metering::with_dynamic_cost`] opts individual
+//! operators out of the flat pricing and into a per-unit charge computed,
+//! and checked, at runtime from the argument already on the stack.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use wasmer::wasmparser::Operator;
+use wasmer::{
+    FunctionMiddleware, GlobalIndex, GlobalInit, GlobalType, Instance, LocalFunctionIndex,
+    MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Mutability, Type,
+};
+
+/// Describes when the `Metering` middleware is allowed to emit the
+/// "have we run out of points?" check.
+///
+/// Every mode subtracts the cost of a basic block from the remaining
+/// points at the same place: right before the operator that ends the
+/// block (a branch, a call, an `end`, ...), after the block's other
+/// instructions have run. The cost is not charged at the entry of the
+/// block. What differs is *when the trap can fire*, which lets
+/// [`MeteringConsumptionMode::Lazy`] skip the comparison-and-branch for
+/// blocks whose overrun can't be observed by anything outside the
+/// instance before it becomes observable again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeteringConsumptionMode {
+    /// Check for exhaustion at the end of every metered basic block,
+    /// right after its cost has been subtracted.
+    ///
+    /// This is the historical behavior: execution never crosses a basic
+    /// block boundary past the configured limit. The instructions of the
+    /// block that exceeds the limit do run before the check at its end
+    /// traps; the trap fires at the same boundary on every re-execution.
+    Eager,
+    /// Only check for exhaustion where the overage could otherwise
+    /// become observable: right before any call (which may reach an
+    /// imported host function), right before an instruction that can
+    /// grow memory or a table, at every `end` and `return`, and at every
+    /// loop entry.
+    ///
+    /// Blocks made up purely of local, arithmetic-only instructions no
+    /// longer pay for a branch at their end. `remaining` is still
+    /// numerically correct at every point execution could trap or cross
+    /// a host boundary; lazy mode only changes *when* the trap fires, so
+    /// a bounded number of extra instructions, memory stores included,
+    /// may run past the limit before the next checkpoint. Loop entries are always a
+    /// checkpoint, even though they aren't otherwise observable, so that
+    /// a purely arithmetic loop body — the case this mode exists to speed
+    /// up — still traps instead of spinning forever on a backward branch
+    /// that never reaches another checkpoint.
+    Lazy,
+}
+
+impl Default for MeteringConsumptionMode {
+    fn default() -> Self {
+        Self::Eager
+    }
+}
+
+/// The coarse category an `Operator` falls into, used to key the execution
+/// histogram collected by [`Metering::calibrating`].
+///
+/// This is deliberately coarser than "one bucket per opcode": it's meant to
+/// tell a user tuning a `cost_function` which *families* of instructions
+/// dominate real execution, not to replace a profiler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OperatorClass {
+    /// Branches, blocks, calls, and everything else that changes control flow.
+    ControlFlow,
+    /// Local and global variable reads/writes.
+    LocalsAndGlobals,
+    /// Loads from and stores to linear memory.
+    MemoryAccess,
+    /// Instructions that query or resize memory or tables, or copy, fill or
+    /// initialize them.
+    MemoryManagement,
+    /// Everything else: arithmetic, comparisons, conversions, etc.
+    Numeric,
+}
+
+impl OperatorClass {
+    /// All the variants, in the order their histogram globals are allocated.
+    const ALL: [OperatorClass; 5] = [
+        OperatorClass::ControlFlow,
+        OperatorClass::LocalsAndGlobals,
+        OperatorClass::MemoryAccess,
+        OperatorClass::MemoryManagement,
+        OperatorClass::Numeric,
+    ];
+
+    fn index(self) -> usize {
+        Self::ALL.iter().position(|&class| class == self).unwrap()
+    }
+
+    /// The name of the export holding this class's histogram counter.
+    fn export_name(self) -> &'static str {
+        match self {
+            OperatorClass::ControlFlow => "wasmer_metering_calibration_histogram_control_flow",
+            OperatorClass::LocalsAndGlobals => {
+                "wasmer_metering_calibration_histogram_locals_and_globals"
+            }
+            OperatorClass::MemoryAccess => "wasmer_metering_calibration_histogram_memory_access",
+            OperatorClass::MemoryManagement => {
+                "wasmer_metering_calibration_histogram_memory_management"
+            }
+            OperatorClass::Numeric => "wasmer_metering_calibration_histogram_numeric",
+        }
+    }
+
+    fn of(operator: &Operator) -> Self {
+        match operator {
+            Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::Else
+            | Operator::End
+            | Operator::Br { .. }
+            | Operator::BrIf { .. }
+            | Operator::BrTable { .. }
+            | Operator::Return
+            | Operator::Call { .. }
+            | Operator::CallIndirect { .. }
+            | Operator::Unreachable
+            | Operator::Nop => OperatorClass::ControlFlow,
+            Operator::LocalGet { .. }
+            | Operator::LocalSet { .. }
+            | Operator::LocalTee { .. }
+            | Operator::GlobalGet { .. }
+            | Operator::GlobalSet { .. } => OperatorClass::LocalsAndGlobals,
+            Operator::MemoryGrow { .. }
+            | Operator::MemorySize { .. }
+            | Operator::MemoryFill { .. }
+            | Operator::MemoryCopy { .. }
+            | Operator::MemoryInit { .. }
+            | Operator::TableGrow { .. }
+            | Operator::TableFill { .. }
+            | Operator::TableCopy { .. }
+            | Operator::TableInit { .. } => OperatorClass::MemoryManagement,
+            Operator::I32Load { .. }
+            | Operator::I64Load { .. }
+            | Operator::F32Load { .. }
+            | Operator::F64Load { .. }
+            | Operator::I32Load8S { .. }
+            | Operator::I32Load8U { .. }
+            | Operator::I32Load16S { .. }
+            | Operator::I32Load16U { .. }
+            | Operator::I64Load8S { .. }
+            | Operator::I64Load8U { .. }
+            | Operator::I64Load16S { .. }
+            | Operator::I64Load16U { .. }
+            | Operator::I64Load32S { .. }
+            | Operator::I64Load32U { .. }
+            | Operator::I32Store { .. }
+            | Operator::I64Store { .. }
+            | Operator::F32Store { .. }
+            | Operator::F64Store { .. }
+            | Operator::I32Store8 { .. }
+            | Operator::I32Store16 { .. }
+            | Operator::I64Store8 { .. }
+            | Operator::I64Store16 { .. }
+            | Operator::I64Store32 { .. } => OperatorClass::MemoryAccess,
+            _ => OperatorClass::Numeric,
+        }
+    }
+}
+
+/// An operator whose cost scales with a runtime argument rather than being
+/// a flat per-opcode constant, as configured with
+/// [`Metering::with_dynamic_cost`].
+///
+/// Each of these takes the quantity of work it's about to do (a page delta,
+/// or a byte length) as its *last* operand, which ends up on top of the
+/// value stack right before the operator executes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DynamicCostOperator {
+    /// `memory.grow`, priced per page requested.
+    MemoryGrow,
+    /// `memory.fill`, priced per byte filled.
+    MemoryFill,
+    /// `memory.copy`, priced per byte copied.
+    MemoryCopy,
+    /// `table.grow`, priced per element requested.
+    TableGrow,
+    /// `table.fill`, priced per element filled.
+    TableFill,
+    /// `table.copy`, priced per element copied.
+    TableCopy,
+}
+
+impl DynamicCostOperator {
+    fn of(operator: &Operator) -> Option<Self> {
+        match operator {
+            Operator::MemoryGrow { .. } => Some(Self::MemoryGrow),
+            Operator::MemoryFill { .. } => Some(Self::MemoryFill),
+            Operator::MemoryCopy { .. } => Some(Self::MemoryCopy),
+            Operator::TableGrow { .. } => Some(Self::TableGrow),
+            Operator::TableFill { .. } => Some(Self::TableFill),
+            Operator::TableCopy { .. } => Some(Self::TableCopy),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a `Metering` middleware enforces a point budget, and how, or
+/// whether it's merely calibrating a `cost_function` (see
+/// [`Metering::calibrating`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MeteringStrategy {
+    /// Subtract costs from a budget and trap on exhaustion, per
+    /// `MeteringConsumptionMode`.
+    Enforce(MeteringConsumptionMode),
+    /// Never trap; just accumulate the points that would have been consumed
+    /// and a per-`OperatorClass` execution histogram.
+    Calibrate,
+}
+
+/// The module-level metering middleware.
+///
+/// See the [module-level documentation](self) for details.
+pub struct Metering<F: Fn(&Operator) -> u64 + Send + Sync> {
+    /// Function that maps each operator to a cost in points.
+    cost_function: Arc<F>,
+    /// The initial budget of points, used to set `remaining_points` at
+    /// instantiation time. Unused in `MeteringStrategy::Calibrate`.
+    initial_limit: u64,
+    /// Whether, and how, the exhaustion check is emitted.
+    strategy: MeteringStrategy,
+    /// The global index for the remaining points, lazily filled in once
+    /// the middleware has registered its globals with the module.
+    remaining_points_global_index: Mutex<Option<GlobalIndex>>,
+    /// The global index for the "points exhausted" flag.
+    points_exhausted_global_index: Mutex<Option<GlobalIndex>>,
+    /// The global index for the calibration points-consumed counter. Only
+    /// allocated in `MeteringStrategy::Calibrate`.
+    consumed_points_global_index: Mutex<Option<GlobalIndex>>,
+    /// The global indexes for the calibration histogram, one per
+    /// `OperatorClass`. Only allocated in `MeteringStrategy::Calibrate`.
+    histogram_global_indices: Mutex<Option<[GlobalIndex; OperatorClass::ALL.len()]>>,
+    /// Per-unit point costs for operators configured with
+    /// [`Metering::with_dynamic_cost`].
+    dynamic_costs: Arc<HashMap<DynamicCostOperator, u64>>,
+    /// A scratch i32 global used to stash a dynamic operator's runtime
+    /// argument while its cost is computed. Only allocated when
+    /// `dynamic_costs` is non-empty.
+    dynamic_scratch_global_index: Mutex<Option<GlobalIndex>>,
+}
+
+impl<F: Fn(&Operator) -> u64 + Send + Sync> Metering<F> {
+    /// Creates a new `Metering` middleware, with
+    /// [`MeteringConsumptionMode::Eager`] semantics.
+    pub fn new(initial_limit: u64, cost_function: F) -> Self {
+        Self::new_with_mode(initial_limit, cost_function, MeteringConsumptionMode::Eager)
+    }
+
+    /// Creates a new `Metering` middleware with an explicit
+    /// [`MeteringConsumptionMode`].
+    pub fn new_with_mode(
+        initial_limit: u64,
+        cost_function: F,
+        consumption_mode: MeteringConsumptionMode,
+    ) -> Self {
+        Self::with_strategy(
+            initial_limit,
+            cost_function,
+            MeteringStrategy::Enforce(consumption_mode),
+        )
+    }
+
+    /// Creates a `Metering` middleware for calibrating a `cost_function`,
+    /// instead of enforcing a limit with one.
+    ///
+    /// The limit check is disabled entirely: execution never traps on
+    /// exhaustion. The middleware still instruments every metered block, but
+    /// accumulates (a) the total points that *would* have been consumed and
+    /// (b) a per-[`OperatorClass`] execution histogram, instead of
+    /// subtracting from a budget and checking it. Run representative
+    /// workloads, then read [`get_points_consumed`] and
+    /// [`get_operator_histogram`] to see which operators dominate real
+    /// execution and derive realistic weights for a `cost_function`.
+    pub fn calibrating(cost_function: F) -> Self {
+        Self::with_strategy(0, cost_function, MeteringStrategy::Calibrate)
+    }
+
+    fn with_strategy(initial_limit: u64, cost_function: F, strategy: MeteringStrategy) -> Self {
+        Self {
+            cost_function: Arc::new(cost_function),
+            initial_limit,
+            strategy,
+            remaining_points_global_index: Mutex::new(None),
+            points_exhausted_global_index: Mutex::new(None),
+            consumed_points_global_index: Mutex::new(None),
+            histogram_global_indices: Mutex::new(None),
+            dynamic_costs: Arc::new(HashMap::new()),
+            dynamic_scratch_global_index: Mutex::new(None),
+        }
+    }
+
+    /// Opts `operator` into dynamic, runtime-argument-scaled pricing: rather
+    /// than the flat cost `cost_function` would otherwise assign it, it is
+    /// charged `per_unit * n`, where `n` is the page delta (for
+    /// `memory.grow`), element delta (for `table.grow`) or byte/element
+    /// length (for the bulk
+    /// `*.fill`/`*.copy` operators) the instruction is about to act on. The
+    /// charge is computed and checked at runtime, right before the
+    /// instruction executes, using the argument already on the operand
+    /// stack — `cost_function` is not consulted for these operators.
+    pub fn with_dynamic_cost(mut self, operator: DynamicCostOperator, per_unit: u64) -> Self {
+        Arc::make_mut(&mut self.dynamic_costs).insert(operator, per_unit);
+        self
+    }
+}
+
+impl<F: Fn(&Operator) -> u64 + Send + Sync + 'static> ModuleMiddleware for Metering<F> {
+    /// Registers the globals used to hold the metering state, and remembers
+    /// their indexes for later use by the function-level middleware.
+    fn generate_global_exports(&self, module_info: &mut wasmer::ModuleInfo) {
+        let remaining_points_global_index = module_info.globals.push(GlobalType {
+            ty: Type::I64,
+            mutability: Mutability::Var,
+        });
+        module_info
+            .global_initializers
+            .push(GlobalInit::I64Const(self.initial_limit as i64));
+        module_info.exports.insert(
+            "wasmer_metering_remaining_points".to_string(),
+            wasmer::ExportIndex::Global(remaining_points_global_index),
+        );
+
+        let points_exhausted_global_index = module_info.globals.push(GlobalType {
+            ty: Type::I32,
+            mutability: Mutability::Var,
+        });
+        module_info
+            .global_initializers
+            .push(GlobalInit::I32Const(0));
+        module_info.exports.insert(
+            "wasmer_metering_points_exhausted".to_string(),
+            wasmer::ExportIndex::Global(points_exhausted_global_index),
+        );
+
+        *self.remaining_points_global_index.lock().unwrap() = Some(remaining_points_global_index);
+        *self.points_exhausted_global_index.lock().unwrap() = Some(points_exhausted_global_index);
+
+        if self.strategy == MeteringStrategy::Calibrate {
+            let consumed_points_global_index = module_info.globals.push(GlobalType {
+                ty: Type::I64,
+                mutability: Mutability::Var,
+            });
+            module_info
+                .global_initializers
+                .push(GlobalInit::I64Const(0));
+            module_info.exports.insert(
+                "wasmer_metering_calibration_consumed_points".to_string(),
+                wasmer::ExportIndex::Global(consumed_points_global_index),
+            );
+            *self.consumed_points_global_index.lock().unwrap() = Some(consumed_points_global_index);
+
+            let mut histogram_global_indices = [consumed_points_global_index; OperatorClass::ALL.len()];
+            for (class, slot) in OperatorClass::ALL.iter().zip(histogram_global_indices.iter_mut()) {
+                let global_index = module_info.globals.push(GlobalType {
+                    ty: Type::I64,
+                    mutability: Mutability::Var,
+                });
+                module_info
+                    .global_initializers
+                    .push(GlobalInit::I64Const(0));
+                module_info
+                    .exports
+                    .insert(class.export_name().to_string(), wasmer::ExportIndex::Global(global_index));
+                *slot = global_index;
+            }
+            *self.histogram_global_indices.lock().unwrap() = Some(histogram_global_indices);
+        }
+
+        if !self.dynamic_costs.is_empty() {
+            let dynamic_scratch_global_index = module_info.globals.push(GlobalType {
+                ty: Type::I32,
+                mutability: Mutability::Var,
+            });
+            module_info
+                .global_initializers
+                .push(GlobalInit::I32Const(0));
+            *self.dynamic_scratch_global_index.lock().unwrap() = Some(dynamic_scratch_global_index);
+        }
+    }
+
+    fn generate_function_middleware(
+        &self,
+        _local_function_index: LocalFunctionIndex,
+    ) -> Box<dyn FunctionMiddleware> {
+        Box::new(FunctionMetering {
+            cost_function: self.cost_function.clone(),
+            strategy: self.strategy,
+            remaining_points_global_index: self
+                .remaining_points_global_index
+                .lock()
+                .unwrap()
+                .expect("metering global indexes not set up"),
+            points_exhausted_global_index: self
+                .points_exhausted_global_index
+                .lock()
+                .unwrap()
+                .expect("metering global indexes not set up"),
+            consumed_points_global_index: self.consumed_points_global_index.lock().unwrap().clone(),
+            histogram_global_indices: self.histogram_global_indices.lock().unwrap().clone(),
+            dynamic_costs: self.dynamic_costs.clone(),
+            dynamic_scratch_global_index: self.dynamic_scratch_global_index.lock().unwrap().clone(),
+            accumulated_cost: 0,
+        })
+    }
+}
+
+impl<F: Fn(&Operator) -> u64 + Send + Sync> fmt::Debug for Metering<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Metering")
+            .field("initial_limit", &self.initial_limit)
+            .field("strategy", &self.strategy)
+            .finish()
+    }
+}
+
+/// The function-level metering middleware, instantiated once per metered
+/// function by [`Metering::generate_function_middleware`].
+struct FunctionMetering<F: Fn(&Operator) -> u64 + Send + Sync> {
+    cost_function: Arc<F>,
+    strategy: MeteringStrategy,
+    remaining_points_global_index: GlobalIndex,
+    points_exhausted_global_index: GlobalIndex,
+    consumed_points_global_index: Option<GlobalIndex>,
+    histogram_global_indices: Option<[GlobalIndex; OperatorClass::ALL.len()]>,
+    dynamic_costs: Arc<HashMap<DynamicCostOperator, u64>>,
+    dynamic_scratch_global_index: Option<GlobalIndex>,
+    /// Points accumulated for the basic block currently being read, not
+    /// yet committed to the `remaining_points`/`consumed_points` global.
+    accumulated_cost: u64,
+}
+
+impl<F: Fn(&Operator) -> u64 + Send + Sync> fmt::Debug for FunctionMetering<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FunctionMetering").finish()
+    }
+}
+
+/// Returns whether `operator` ends the current basic block, i.e. whether
+/// control flow may no longer fall straight through to the next operator.
+fn is_basic_block_boundary(operator: &Operator) -> bool {
+    matches!(
+        operator,
+        Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::Else
+            | Operator::End
+            | Operator::Br { .. }
+            | Operator::BrIf { .. }
+            | Operator::BrTable { .. }
+            | Operator::Return
+            | Operator::Call { .. }
+            | Operator::CallIndirect { .. }
+            | Operator::Unreachable
+    )
+}
+
+/// Returns whether an overrun crossing `operator` would become observable
+/// outside of the current function activation: any call, since it may reach
+/// an import (the host can observe how many points remain), an instruction
+/// that grows memory or a table (the host-visible size would otherwise be
+/// wrong), or an `end`/`return` (the caller's accounting depends on it). Loop entries
+/// are also treated as a checkpoint, even though they aren't otherwise
+/// observable: a backward branch can reach one arbitrarily many times
+/// without ever reaching `Call`/`Return`/`MemoryGrow`/`End`, and without a
+/// check here a purely arithmetic loop could spin forever past the limit.
+fn is_observable_checkpoint(operator: &Operator) -> bool {
+    matches!(
+        operator,
+        Operator::Call { .. }
+            | Operator::CallIndirect { .. }
+            | Operator::Return
+            | Operator::End
+            | Operator::MemoryGrow { .. }
+            | Operator::TableGrow { .. }
+            | Operator::Loop { .. }
+    )
+}
+
+impl<F: Fn(&Operator) -> u64 + Send + Sync> FunctionMetering<F> {
+    /// Commits `self.accumulated_cost` to the shared `remaining_points`/
+    /// `consumed_points` global and resets it to zero.
+    ///
+    /// Must run before anything that reads that global (an exhaustion
+    /// check, or a dynamic charge's read-modify-write of the same global),
+    /// since those reads need to see every flat cost charged so far in the
+    /// current basic block, not just whatever was committed at the last
+    /// flush.
+    fn flush_accumulated_cost<'a>(&mut self, state: &mut MiddlewareReaderState<'a>) {
+        if self.accumulated_cost == 0 {
+            return;
+        }
+
+        let target_global_index = match self.strategy {
+            MeteringStrategy::Enforce(_) => self.remaining_points_global_index,
+            MeteringStrategy::Calibrate => self
+                .consumed_points_global_index
+                .expect("calibration globals not set up"),
+        };
+
+        state.push_operator(Operator::GlobalGet {
+            global_index: target_global_index.as_u32(),
+        });
+        state.push_operator(Operator::I64Const {
+            value: self.accumulated_cost as i64,
+        });
+        // `remaining_points -= accumulated_cost` when enforcing a limit,
+        // committed unconditionally so that `remaining_points` is always
+        // numerically correct at any trap or host boundary, regardless of
+        // whether we check it now; `consumed_points += accumulated_cost`
+        // when calibrating, since there is no budget to subtract from.
+        match self.strategy {
+            MeteringStrategy::Enforce(_) => state.push_operator(Operator::I64Sub),
+            MeteringStrategy::Calibrate => state.push_operator(Operator::I64Add),
+        }
+        state.push_operator(Operator::GlobalSet {
+            global_index: target_global_index.as_u32(),
+        });
+        self.accumulated_cost = 0;
+    }
+}
+
+impl<F: Fn(&Operator) -> u64 + Send + Sync> FunctionMiddleware for FunctionMetering<F> {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        let dynamic_cost = DynamicCostOperator::of(&operator)
+            .and_then(|dynamic_operator| self.dynamic_costs.get(&dynamic_operator).copied());
+
+        if dynamic_cost.is_none() {
+            self.accumulated_cost += (self.cost_function)(&operator);
+        }
+
+        if self.strategy == MeteringStrategy::Calibrate {
+            let histogram_global_index = self
+                .histogram_global_indices
+                .as_ref()
+                .expect("calibration globals not set up")[OperatorClass::of(&operator).index()];
+            // `histogram[class] += 1`, unconditionally, for every operator:
+            // calibration counts how many times each class actually executes.
+            state.push_operator(Operator::GlobalGet {
+                global_index: histogram_global_index.as_u32(),
+            });
+            state.push_operator(Operator::I64Const { value: 1 });
+            state.push_operator(Operator::I64Add);
+            state.push_operator(Operator::GlobalSet {
+                global_index: histogram_global_index.as_u32(),
+            });
+        }
+
+        if let Some(per_unit) = dynamic_cost {
+            // The per-unit charge below reads and rewrites the same global
+            // a flush would target; flush first so the read sees every
+            // flat cost charged earlier in this block instead of a stale
+            // value that hasn't had `accumulated_cost` subtracted yet.
+            self.flush_accumulated_cost(state);
+
+            let scratch_global_index = self
+                .dynamic_scratch_global_index
+                .expect("dynamic cost scratch global not set up");
+            let target_global_index = match self.strategy {
+                MeteringStrategy::Enforce(_) => self.remaining_points_global_index,
+                MeteringStrategy::Calibrate => self
+                    .consumed_points_global_index
+                    .expect("calibration globals not set up"),
+            };
+
+            // The dynamic argument (page delta, or byte/element length) is
+            // already on top of the stack, pushed by the code preceding this
+            // operator. Stash it in a scratch global so we can both use it
+            // for the charge below and hand it back to the real operator
+            // afterwards, since Wasm has no instruction to duplicate a stack
+            // value without a local or global to round-trip it through.
+            state.push_operator(Operator::GlobalSet {
+                global_index: scratch_global_index.as_u32(),
+            });
+
+            state.push_operator(Operator::GlobalGet {
+                global_index: target_global_index.as_u32(),
+            });
+            state.push_operator(Operator::GlobalGet {
+                global_index: scratch_global_index.as_u32(),
+            });
+            state.push_operator(Operator::I64ExtendI32U);
+            state.push_operator(Operator::I64Const {
+                value: per_unit as i64,
+            });
+            state.push_operator(Operator::I64Mul);
+            match self.strategy {
+                MeteringStrategy::Enforce(_) => state.push_operator(Operator::I64Sub),
+                MeteringStrategy::Calibrate => state.push_operator(Operator::I64Add),
+            }
+            state.push_operator(Operator::GlobalSet {
+                global_index: target_global_index.as_u32(),
+            });
+
+            if let MeteringStrategy::Enforce(_) = self.strategy {
+                // `if remaining_points < 0 { points_exhausted = 1; unreachable }`,
+                // checked immediately: a dynamic charge is precise and can
+                // jump past the limit in one shot, so unlike a static block's
+                // flat cost it can't wait for the next checkpoint.
+                state.push_operator(Operator::GlobalGet {
+                    global_index: self.remaining_points_global_index.as_u32(),
+                });
+                state.push_operator(Operator::I64Const { value: 0 });
+                state.push_operator(Operator::I64LtS);
+                state.push_operator(Operator::If {
+                    ty: wasmer::wasmparser::TypeOrFuncType::Type(
+                        wasmer::wasmparser::Type::EmptyBlockType,
+                    ),
+                });
+                state.push_operator(Operator::I32Const { value: 1 });
+                state.push_operator(Operator::GlobalSet {
+                    global_index: self.points_exhausted_global_index.as_u32(),
+                });
+                state.push_operator(Operator::Unreachable);
+                state.push_operator(Operator::End);
+            }
+
+            // Hand the dynamic argument back to the real operator, which
+            // still expects to find it on top of the stack.
+            state.push_operator(Operator::GlobalGet {
+                global_index: scratch_global_index.as_u32(),
+            });
+        }
+
+        let is_boundary = is_basic_block_boundary(&operator);
+        let is_checkpoint = match self.strategy {
+            MeteringStrategy::Enforce(MeteringConsumptionMode::Eager) => is_boundary,
+            MeteringStrategy::Enforce(MeteringConsumptionMode::Lazy) => {
+                is_observable_checkpoint(&operator)
+            }
+            MeteringStrategy::Calibrate => false,
+        };
+        // A checkpoint always implies a flush: an exhaustion check reads
+        // `remaining_points`/`consumed_points`, and that read must already
+        // reflect every flat cost charged earlier in the block, not just
+        // whatever was committed at the last block boundary.
+        let should_flush = is_boundary || is_checkpoint;
+        // A dynamic-cost operator already emitted its own exhaustion check
+        // above, against the same `remaining_points` global; skip emitting
+        // a second, identical one here even if it's also a checkpoint.
+        let should_check = is_checkpoint
+            && !(dynamic_cost.is_some() && matches!(self.strategy, MeteringStrategy::Enforce(_)));
+
+        if should_flush {
+            self.flush_accumulated_cost(state);
+        }
+
+        // The check for a `loop` goes right after the `loop` opcode, at the
+        // start of its body, which is where a backward branch lands; in
+        // front of it, the check would run only once, before the first
+        // iteration. The flush above stays in front, so it isn't repeated.
+        let is_loop = matches!(operator, Operator::Loop { .. });
+        if is_loop {
+            state.push_operator(operator.clone());
+        }
+
+        if should_check {
+            // `if remaining_points < 0 { points_exhausted = 1; unreachable }`
+            state.push_operator(Operator::GlobalGet {
+                global_index: self.remaining_points_global_index.as_u32(),
+            });
+            state.push_operator(Operator::I64Const { value: 0 });
+            state.push_operator(Operator::I64LtS);
+            state.push_operator(Operator::If {
+                ty: wasmer::wasmparser::TypeOrFuncType::Type(wasmer::wasmparser::Type::EmptyBlockType),
+            });
+            state.push_operator(Operator::I32Const { value: 1 });
+            state.push_operator(Operator::GlobalSet {
+                global_index: self.points_exhausted_global_index.as_u32(),
+            });
+            state.push_operator(Operator::Unreachable);
+            state.push_operator(Operator::End);
+        }
+
+        if !is_loop {
+            state.push_operator(operator);
+        }
+        Ok(())
+    }
+}
+
+fn remaining_points_global(instance: &Instance) -> wasmer::Global {
+    instance
+        .exports
+        .get_global("wasmer_metering_remaining_points")
+        .expect("instance was not compiled with the `Metering` middleware")
+        .clone()
+}
+
+fn points_exhausted_global(instance: &Instance) -> wasmer::Global {
+    instance
+        .exports
+        .get_global("wasmer_metering_points_exhausted")
+        .expect("instance was not compiled with the `Metering` middleware")
+        .clone()
+}
+
+/// Gets the number of points remaining before execution traps.
+///
+/// Once the limit has been reached, this keeps returning `0`; use
+/// [`set_remaining_points`] to replenish the budget before calling into the
+/// instance again.
+pub fn get_remaining_points(instance: &Instance) -> u64 {
+    let exhausted = points_exhausted_global(instance)
+        .get()
+        .i32()
+        .expect("`wasmer_metering_points_exhausted` has an unexpected type");
+    if exhausted != 0 {
+        return 0;
+    }
+
+    let remaining = remaining_points_global(instance)
+        .get()
+        .i64()
+        .expect("`wasmer_metering_remaining_points` has an unexpected type");
+    remaining as u64
+}
+
+/// Sets the remaining points, clearing the "exhausted" flag if it was set.
+pub fn set_remaining_points(instance: &Instance, points: u64) {
+    remaining_points_global(instance)
+        .set((points as i64).into())
+        .expect("failed to set `wasmer_metering_remaining_points`");
+    points_exhausted_global(instance)
+        .set(0i32.into())
+        .expect("failed to set `wasmer_metering_points_exhausted`");
+}
+
+/// The number of points remaining, or whether they have already been
+/// exhausted, as returned by [`metering_points`].
+///
+/// This is the accessor host functions should reach for: unlike
+/// [`get_remaining_points`], which saturates at `0`, it lets a host function
+/// tell "no points left" apart from "exactly zero points remain".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeteringPoints {
+    /// The given number of points is still available for execution.
+    Remaining(u64),
+    /// The instance ran out of points; a call to [`try_charge`] fails. The
+    /// injected Wasm code doesn't read this flag: it traps at its next
+    /// exhaustion check that finds the remaining points below zero.
+    Exhausted,
+}
+
+/// Gets the remaining points, distinguishing "exhausted" from "zero".
+pub fn metering_points(instance: &Instance) -> MeteringPoints {
+    let exhausted = points_exhausted_global(instance)
+        .get()
+        .i32()
+        .expect("`wasmer_metering_points_exhausted` has an unexpected type");
+    if exhausted != 0 {
+        return MeteringPoints::Exhausted;
+    }
+
+    let remaining = remaining_points_global(instance)
+        .get()
+        .i64()
+        .expect("`wasmer_metering_remaining_points` has an unexpected type");
+    MeteringPoints::Remaining(remaining as u64)
+}
+
+/// An error returned by [`try_charge`] when an instance doesn't have enough
+/// points left to cover the requested charge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeteringError {
+    /// The number of points that were requested.
+    pub requested: u64,
+    /// The number of points that were actually available.
+    pub remaining: u64,
+}
+
+impl fmt::Display for MeteringError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "not enough metering points: requested {}, only {} remaining",
+            self.requested, self.remaining
+        )
+    }
+}
+
+impl std::error::Error for MeteringError {}
+
+/// Charges `points` against `instance`'s remaining metering budget.
+///
+/// This is meant to be called from inside a host (imported) function, using
+/// whatever context/env that function receives to reach the `Instance` it
+/// was called on. Host functions don't get charged by the `Metering`
+/// middleware itself, since it can only see Wasm `Operator`s, so a host
+/// function that does real work should charge for it explicitly with this
+/// helper.
+///
+/// On success, the charge is committed to the shared `remaining_points`
+/// global immediately, both so that a subsequent call to
+/// [`get_remaining_points`] sees it and so that a host function that
+/// re-enters Wasm (directly, or indirectly through another import) can't
+/// leave a stale, too-low consumed value lying around in a local variable
+/// that would get double-charged, or under-charged, the next time a
+/// host-boundary is crossed. Always call `try_charge` right before doing the
+/// chargeable work, rather than caching its result across a reentrant call.
+///
+/// If there aren't enough points remaining, this saturates the remaining
+/// points at `0`, sets the "exhausted" flag (so [`metering_points`] reports
+/// [`MeteringPoints::Exhausted`]), and returns [`MeteringError`]. It is up to
+/// the caller to turn that into a trap, e.g. by returning an `Err` from the
+/// host function; the injected Wasm code doesn't read the flag, and only
+/// traps once a later nonzero charge takes the remaining points below zero.
+pub fn try_charge(instance: &Instance, points: u64) -> Result<(), MeteringError> {
+    match metering_points(instance) {
+        MeteringPoints::Exhausted => {
+            points_exhausted_global(instance)
+                .set(1i32.into())
+                .expect("failed to set `wasmer_metering_points_exhausted`");
+            Err(MeteringError {
+                requested: points,
+                remaining: 0,
+            })
+        }
+        MeteringPoints::Remaining(remaining) if remaining < points => {
+            remaining_points_global(instance)
+                .set(0i64.into())
+                .expect("failed to set `wasmer_metering_remaining_points`");
+            points_exhausted_global(instance)
+                .set(1i32.into())
+                .expect("failed to set `wasmer_metering_points_exhausted`");
+            Err(MeteringError {
+                requested: points,
+                remaining,
+            })
+        }
+        MeteringPoints::Remaining(remaining) => {
+            remaining_points_global(instance)
+                .set(((remaining - points) as i64).into())
+                .expect("failed to set `wasmer_metering_remaining_points`");
+            Ok(())
+        }
+    }
+}
+
+/// Gets the total number of points that would have been consumed so far by
+/// an instance compiled with [`Metering::calibrating`].
+///
+/// # Panics
+///
+/// Panics if `instance` wasn't compiled with `Metering::calibrating`.
+pub fn get_points_consumed(instance: &Instance) -> u64 {
+    let consumed = instance
+        .exports
+        .get_global("wasmer_metering_calibration_consumed_points")
+        .expect("instance was not compiled with `Metering::calibrating`")
+        .get()
+        .i64()
+        .expect("`wasmer_metering_calibration_consumed_points` has an unexpected type");
+    consumed as u64
+}
+
+/// Gets, for an instance compiled with [`Metering::calibrating`], how many
+/// times each [`OperatorClass`] was actually executed.
+///
+/// # Panics
+///
+/// Panics if `instance` wasn't compiled with `Metering::calibrating`.
+pub fn get_operator_histogram(instance: &Instance) -> HashMap<OperatorClass, u64> {
+    OperatorClass::ALL
+        .iter()
+        .map(|&class| {
+            let count = instance
+                .exports
+                .get_global(class.export_name())
+                .expect("instance was not compiled with `Metering::calibrating`")
+                .get()
+                .i64()
+                .expect("calibration histogram global has an unexpected type");
+            (class, count as u64)
+        })
+        .collect()
+}